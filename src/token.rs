@@ -1,7 +1,62 @@
-use std::collections::LinkedList;
+use std::collections::{LinkedList, VecDeque};
+use std::io::Read;
+#[cfg(feature = "span-locations")]
+use std::ops::Range;
 
 // Parser template file into a series of tokens
 
+/// A line/column position within the source template.
+///
+/// Lines and columns are both zero-indexed, mirroring the convention used
+/// by `proc-macro2`'s `LineColumn`.
+#[cfg(feature = "span-locations")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LineColumn {
+    pub line: usize,
+    pub column: usize
+}
+
+/// The region of source a token was read from, as both line/column
+/// positions and a byte offset range.
+#[cfg(feature = "span-locations")]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: LineColumn,
+    pub end: LineColumn,
+    pub byte_range: Range<usize>
+}
+
+/// What kind of problem a `LexError` reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexErrorKind {
+    /// A `{{` (or `{{{`) tag was opened but input ended before its
+    /// matching close was found.
+    UnterminatedTag,
+    /// A tag closed (`}}`) without any name or sigil between the
+    /// delimiters, e.g. `{{}}`.
+    EmptyTagName,
+    /// A `{{=...=}}` set-delimiter tag didn't match `=open ws+ close=rm`.
+    MalformedSetDelimiter
+}
+
+/// A recoverable lexing problem, together with where it was detected.
+/// Pushed onto an accumulator (see `Tokenlizer::errors`) rather than
+/// aborting the token stream, so a single malformed tag doesn't hide
+/// everything lexed around it.
+#[cfg(feature = "span-locations")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub span: Span
+}
+
+#[cfg(not(feature = "span-locations"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub byte_offset: usize
+}
+
 // All tokens appears in mustache
 #[derive(Debug, PartialEq)]
 pub enum Token {
@@ -22,8 +77,31 @@ pub enum Token {
     Slash,
     // regex: ^
     Hat,
-    // Regex: (\w|\d)+
-    Id(String)
+    // Regex: (\w|\d)+, dots included so dotted paths like `a.b.c` tokenize
+    // as a single Id.
+    Id(String),
+    // regex: =\S+\s+\S+=
+    // The set-delimiter tag, e.g. `{{=<% %>=}}`.
+    SetDelimiter { open: String, close: String },
+    // regex: !
+    // Comments; the body is consumed and discarded, no token is emitted for it.
+    Bang,
+    // regex: >
+    GreaterThan,
+    // Emitted in place of `LMustache`/`RMustache` for the opening `lm{`
+    // and closing `rm}` of a triple-mustache (unescaped) tag, e.g.
+    // `{{{ name }}}`.
+    TripleLMustache,
+    TripleRMustache,
+    // regex: \.
+    // The implicit iterator, e.g. `{{.}}`.
+    Dot,
+    // Emitted right after the `RMustache`/`Bang` of a standalone section,
+    // comment, or partial tag (one that sits alone on its line). The
+    // leading blanks were already trimmed off the preceding `Text` token
+    // and the trailing blanks + newline were consumed without a token, so
+    // the renderer sees this marker instead of having to infer it.
+    StandaloneMarker
 }
 
 enum State {
@@ -31,6 +109,245 @@ enum State {
     SyntaxToken
 }
 
+// How big a prefix to buffer when no BOM is present, in order to sniff
+// whether the input is valid UTF-8.
+const SNIFF_LEN: usize = 4096;
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1252
+}
+
+enum DecodeState {
+    // Encoding not yet determined; buffers bytes until the first read.
+    Auto,
+    Decode(Encoding)
+}
+
+/// Adapts a raw byte stream into the `char` stream `Tokenlizer` consumes,
+/// so templates saved from arbitrary editors don't need decoding up front.
+///
+/// The first read sniffs a BOM (UTF-8, UTF-16LE, UTF-16BE). Absent a BOM,
+/// it buffers a prefix of the input and picks UTF-8 if that prefix is
+/// valid UTF-8, otherwise falls back to the single-byte `windows-1252`
+/// legacy encoding.
+pub struct CharDecoder<R> {
+    reader : R,
+    state  : DecodeState,
+    // Raw bytes read but not yet decoded into a char.
+    bytes  : VecDeque<u8>,
+    // Decoded chars queued ahead of the byte buffer; `next` hands these out
+    // first. None of the decoders below push onto it.
+    chars  : VecDeque<char>,
+    eof    : bool
+}
+
+impl<R: Read> CharDecoder<R> {
+    pub fn new(reader: R) -> CharDecoder<R> {
+        CharDecoder {
+            reader : reader,
+            state  : DecodeState::Auto,
+            bytes  : VecDeque::new(),
+            chars  : VecDeque::new(),
+            eof    : false
+        }
+    }
+
+    // Ensure at least `want` bytes are buffered, unless EOF is hit first.
+    fn fill(&mut self, want: usize) {
+        let mut chunk = [0u8; 1024];
+        while !self.eof && self.bytes.len() < want {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.bytes.extend(chunk[..n].iter()),
+                Err(_) => self.eof = true
+            }
+        }
+    }
+
+    fn sniff(&mut self) {
+        self.fill(3);
+
+        let encoding = if self.starts_with_bytes(&[0xEF, 0xBB, 0xBF]) {
+            self.bytes.drain(..3);
+            Encoding::Utf8
+        } else if self.starts_with_bytes(&[0xFF, 0xFE]) {
+            self.bytes.drain(..2);
+            Encoding::Utf16Le
+        } else if self.starts_with_bytes(&[0xFE, 0xFF]) {
+            self.bytes.drain(..2);
+            Encoding::Utf16Be
+        } else {
+            self.fill(SNIFF_LEN);
+            let prefix : Vec<u8> = self.bytes.iter().cloned().collect();
+            let valid = match std::str::from_utf8(&prefix) {
+                Ok(_) => true,
+                // A char cut off by the end of the sniffed prefix (rather
+                // than by EOF) doesn't make the input invalid.
+                Err(e) => e.error_len().is_none() && !self.eof
+            };
+            if valid {
+                Encoding::Utf8
+            } else {
+                Encoding::Windows1252
+            }
+        };
+
+        self.state = DecodeState::Decode(encoding);
+    }
+
+    fn starts_with_bytes(&self, prefix: &[u8]) -> bool {
+        self.bytes.len() >= prefix.len()
+            && self.bytes.iter().take(prefix.len()).eq(prefix.iter())
+    }
+
+    fn decode_utf8(&mut self) -> Option<char> {
+        loop {
+            self.fill(4);
+            if self.bytes.is_empty() {
+                return None;
+            }
+
+            let sample : Vec<u8> = self.bytes.iter().cloned().take(4).collect();
+            match std::str::from_utf8(&sample) {
+                Ok(s) => {
+                    let ch = s.chars().next().unwrap();
+                    for _ in 0..ch.len_utf8() { self.bytes.pop_front(); }
+                    return Some(ch);
+                }
+                Err(e) if e.valid_up_to() > 0 => {
+                    let s = std::str::from_utf8(&sample[..e.valid_up_to()]).unwrap();
+                    let ch = s.chars().next().unwrap();
+                    for _ in 0..ch.len_utf8() { self.bytes.pop_front(); }
+                    return Some(ch);
+                }
+                Err(_) if sample.len() < 4 && !self.eof => {
+                    // Sequence looks truncated; read more and retry.
+                    continue;
+                }
+                Err(_) => {
+                    // Not a valid sequence even with more bytes available:
+                    // skip the bad byte so the stream keeps making progress.
+                    self.bytes.pop_front();
+                    return Some('\u{FFFD}');
+                }
+            }
+        }
+    }
+
+    fn decode_utf16_unit(&mut self, le: bool) -> Option<u16> {
+        self.fill(2);
+        if self.bytes.len() < 2 {
+            self.bytes.clear();
+            return None;
+        }
+        let b0 = self.bytes.pop_front().unwrap();
+        let b1 = self.bytes.pop_front().unwrap();
+        Some(if le { u16::from_le_bytes([b0, b1]) } else { u16::from_be_bytes([b0, b1]) })
+    }
+
+    fn decode_utf16(&mut self, le: bool) -> Option<char> {
+        let unit = self.decode_utf16_unit(le)?;
+
+        if (0xD800..0xDC00).contains(&unit) {
+            let low = match self.decode_utf16_unit(le) {
+                Some(low) if (0xDC00..0xE000).contains(&low) => low,
+                _ => return Some('\u{FFFD}')
+            };
+            let c = 0x10000 + ((unit as u32 - 0xD800) << 10) + (low as u32 - 0xDC00);
+            Some(char::from_u32(c).unwrap_or('\u{FFFD}'))
+        } else {
+            Some(char::from_u32(unit as u32).unwrap_or('\u{FFFD}'))
+        }
+    }
+
+    fn decode_windows1252(&mut self) -> Option<char> {
+        self.fill(1);
+        self.bytes.pop_front().map(windows1252_to_char)
+    }
+}
+
+// windows-1252 agrees with Latin-1 everywhere except the 0x80..=0x9F
+// range, where a handful of bytes map to punctuation/typography code
+// points instead of C1 control characters.
+fn windows1252_to_char(byte: u8) -> char {
+    match byte {
+        0x80 => '\u{20AC}',
+        0x82 => '\u{201A}',
+        0x83 => '\u{0192}',
+        0x84 => '\u{201E}',
+        0x85 => '\u{2026}',
+        0x86 => '\u{2020}',
+        0x87 => '\u{2021}',
+        0x88 => '\u{02C6}',
+        0x89 => '\u{2030}',
+        0x8A => '\u{0160}',
+        0x8B => '\u{2039}',
+        0x8C => '\u{0152}',
+        0x8E => '\u{017D}',
+        0x91 => '\u{2018}',
+        0x92 => '\u{2019}',
+        0x93 => '\u{201C}',
+        0x94 => '\u{201D}',
+        0x95 => '\u{2022}',
+        0x96 => '\u{2013}',
+        0x97 => '\u{2014}',
+        0x98 => '\u{02DC}',
+        0x99 => '\u{2122}',
+        0x9A => '\u{0161}',
+        0x9B => '\u{203A}',
+        0x9C => '\u{0153}',
+        0x9E => '\u{017E}',
+        0x9F => '\u{0178}',
+        _ => byte as char
+    }
+}
+
+impl<R: Read> Iterator for CharDecoder<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if let Some(ch) = self.chars.pop_front() {
+            return Some(ch);
+        }
+
+        if let DecodeState::Auto = self.state {
+            self.sniff();
+        }
+
+        match self.state {
+            DecodeState::Decode(Encoding::Utf8) => self.decode_utf8(),
+            DecodeState::Decode(Encoding::Utf16Le) => self.decode_utf16(true),
+            DecodeState::Decode(Encoding::Utf16Be) => self.decode_utf16(false),
+            DecodeState::Decode(Encoding::Windows1252) => self.decode_windows1252(),
+            DecodeState::Auto => unreachable!("sniff() always sets a concrete encoding")
+        }
+    }
+}
+
+// Cursor position tracked while reading, in order to build `Span`s. Kept
+// separate from the public `LineColumn` so we can store the byte offset
+// alongside it without exposing that as part of the public type.
+#[cfg(feature = "span-locations")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Pos {
+    offset: usize,
+    line: usize,
+    column: usize
+}
+
+// A lightweight position mark used to remember where a tag opened, so a
+// `LexError` can report it. With `span-locations` this is the full cursor
+// (to build a `Span`); otherwise it's just the byte offset, which (unlike
+// line/column) a push_back can undo by simple subtraction.
+#[cfg(feature = "span-locations")]
+type Mark = Pos;
+#[cfg(not(feature = "span-locations"))]
+type Mark = usize;
+
 pub struct Tokenlizer<'a, T: 'a> {
     reader : &'a mut T,
     // We can push back some char if read more char than we want.
@@ -38,12 +355,43 @@ pub struct Tokenlizer<'a, T: 'a> {
     // Whether or not current position is between lm and rm
     state  : State,
     lm     : String,
-    rm     : String
+    rm     : String,
+    // Whether we're between a `{{{` and its matching `}}}`.
+    triple : bool,
+    // Where the currently open tag's `{{` started, for LexError positions.
+    tag_start       : Option<Mark>,
+    // Whether the currently open tag has produced any content yet.
+    tag_has_content : bool,
+    // Whether only blanks (spaces/tabs) have been read since the last
+    // newline (or the start of input). Used to recognize standalone tags.
+    at_line_start   : bool,
+    // Whether the tag currently open is a standalone section/comment/
+    // partial, so its close should also eat the line's trailing blanks and
+    // newline and queue a `StandaloneMarker`. Set by `read_token` when
+    // `peek_standalone_tag` succeeds.
+    standalone_pending : bool,
+    // A token computed ahead of its turn, returned on the next call to
+    // `read_token` before anything else runs.
+    pending_token   : Option<Token>,
+    // Recoverable lexing problems encountered so far; see `errors()`.
+    errors          : Vec<LexError>,
+    // Current cursor position, used to build `Span`s.
+    #[cfg(feature = "span-locations")]
+    pos    : Pos,
+    // Cursor position before each char was read, so push_back can restore
+    // it exactly (a newline loses the prior column, so we can't recompute
+    // it from the char alone).
+    #[cfg(feature = "span-locations")]
+    undo   : Vec<Pos>,
+    // Running byte offset, used as a `Mark` when span-locations tracking
+    // isn't enabled.
+    #[cfg(not(feature = "span-locations"))]
+    byte_offset : usize
 }
 
 fn is_id(ch : char) -> bool {
-    // Match [a-zA-Z0-9_]
-    ch.is_digit(36) || ch == '_'
+    // Match [a-zA-Z0-9_.], the dot allowing dotted paths like `a.b.c`.
+    ch.is_digit(36) || ch == '_' || ch == '.'
 }
 
 
@@ -55,15 +403,45 @@ impl<'a, T : Iterator<Item = char>> Tokenlizer<'a, T> {
             buf    : LinkedList::new(),
             state  : State::Normal,
             lm     : lm.to_string(),
-            rm     : rm.to_string()
+            rm     : rm.to_string(),
+            triple : false,
+            tag_start       : None,
+            tag_has_content : false,
+            at_line_start   : true,
+            standalone_pending : false,
+            pending_token   : None,
+            errors          : Vec::new(),
+            #[cfg(feature = "span-locations")]
+            pos    : Pos::default(),
+            #[cfg(feature = "span-locations")]
+            undo   : Vec::new(),
+            #[cfg(not(feature = "span-locations"))]
+            byte_offset : 0
         }
     }
 
     // Char stream operations
     fn read(&mut self) -> Option<char> {
-        self
-            .buf.pop_front()
-            .or_else(|| self.reader.next())
+        let ch = self.buf.pop_front().or_else(|| self.reader.next())?;
+
+        #[cfg(feature = "span-locations")]
+        {
+            self.undo.push(self.pos);
+            self.pos.offset += ch.len_utf8();
+            if ch == '\n' {
+                self.pos.line += 1;
+                self.pos.column = 0;
+            } else {
+                self.pos.column += 1;
+            }
+        }
+
+        #[cfg(not(feature = "span-locations"))]
+        {
+            self.byte_offset += ch.len_utf8();
+        }
+
+        Some(ch)
     }
 
     fn read_str(&mut self, size : usize) -> Option<String> {
@@ -81,6 +459,7 @@ impl<'a, T : Iterator<Item = char>> Tokenlizer<'a, T> {
     }
 
     fn read_until(&mut self, until : &str) -> Option<String> {
+        let until_len = until.chars().count();
         let mut buf = String::new();
         // Read until end of file or encounter "until"
         while let Some(c) = self.read() {
@@ -91,6 +470,11 @@ impl<'a, T : Iterator<Item = char>> Tokenlizer<'a, T> {
                 buf.truncate(len);
                 break;
             }
+            // Everything but the trailing `until_len` chars just read is
+            // durably consumed (it'll never be pushed back), so drop its
+            // undo entries instead of letting them pile up for the rest
+            // of the document.
+            self.trim_undo(until_len);
         }
 
         if buf.len() > 0 {
@@ -100,13 +484,16 @@ impl<'a, T : Iterator<Item = char>> Tokenlizer<'a, T> {
         }
     }
 
-    fn read_while<F>(&mut self, pred : F) -> Option<String> where 
+    fn read_while<F>(&mut self, pred : F) -> Option<String> where
         F: Fn(char) -> bool
     {
         let mut buf = String::new();
         while let Some(c) = self.read() {
             if pred(c) {
                 buf.push(c);
+                // `c` is durably consumed; only a char read after it could
+                // still be pushed back.
+                self.trim_undo(0);
             } else {
                 self.push_back_char(c);
                 break;
@@ -120,7 +507,31 @@ impl<'a, T : Iterator<Item = char>> Tokenlizer<'a, T> {
         }
     }
 
+    // Drop undo entries beyond the last `keep`, once we know the chars
+    // they belong to are durably consumed and can never be pushed back.
+    // Keeps `undo` bounded by lookahead depth instead of document length.
+    #[cfg(feature = "span-locations")]
+    fn trim_undo(&mut self, keep: usize) {
+        if self.undo.len() > keep {
+            let excess = self.undo.len() - keep;
+            self.undo.drain(0..excess);
+        }
+    }
+
+    #[cfg(not(feature = "span-locations"))]
+    fn trim_undo(&mut self, _keep: usize) {}
+
     fn push_back_char(&mut self, ch: char) {
+        #[cfg(feature = "span-locations")]
+        {
+            self.pos = self.undo.pop().expect("push_back without matching read");
+        }
+
+        #[cfg(not(feature = "span-locations"))]
+        {
+            self.byte_offset -= ch.len_utf8();
+        }
+
         self.buf.push_front(ch);
     }
 
@@ -157,7 +568,236 @@ impl<'a, T : Iterator<Item = char>> Tokenlizer<'a, T> {
         }
     }
 
+    #[cfg(feature = "span-locations")]
+    fn mark(&self) -> Mark {
+        self.pos
+    }
+
+    #[cfg(not(feature = "span-locations"))]
+    fn mark(&self) -> Mark {
+        self.byte_offset
+    }
+
+    #[cfg(feature = "span-locations")]
+    fn lex_error(&self, kind: LexErrorKind, start: Mark) -> LexError {
+        LexError {
+            kind,
+            span: Span {
+                start: LineColumn { line: start.line, column: start.column },
+                end: LineColumn { line: self.pos.line, column: self.pos.column },
+                byte_range: start.offset..self.pos.offset
+            }
+        }
+    }
+
+    #[cfg(not(feature = "span-locations"))]
+    fn lex_error(&self, kind: LexErrorKind, start: Mark) -> LexError {
+        LexError { kind, byte_offset: start }
+    }
+
+    /// Recoverable lexing problems encountered so far (unterminated tags,
+    /// empty tag names, malformed set-delimiter syntax). The token stream
+    /// keeps going past these rather than aborting silently.
+    pub fn errors(&self) -> &[LexError] {
+        &self.errors
+    }
+
+    // Parse the body of a set-delimiter tag (`{{=<% %>=}}`) after the
+    // leading `=` has already been consumed. `rm` is the *current* right
+    // delimiter, since the tag itself still closes with the old one.
+    fn read_set_delimiter(&mut self, rm: &str) -> Option<Token> {
+        let start = self.tag_start;
+
+        let open = self.read_while(|c| c != ' ');
+        self.skip_ws();
+        let close_marker = format!("={}", rm);
+        let close = self.read_until(&close_marker);
+        let closed = self.starts_with(&close_marker);
+
+        self.tag_start = None;
+        self.state = State::Normal;
+
+        if let (Some(open), Some(close), true) = (open, close, closed) {
+            self.lm = open.clone();
+            self.rm = close.clone();
+            return Some(Token::SetDelimiter { open, close });
+        }
+
+        if let Some(start) = start {
+            self.errors.push(self.lex_error(LexErrorKind::MalformedSetDelimiter, start));
+        }
+        // The malformed tag has been consumed; keep lexing what follows it.
+        self.read_token()
+    }
+
+    // Parse a comment tag (`{{! ... }}`) after the leading `!` has already
+    // been consumed, discarding its body entirely.
+    fn read_comment(&mut self, rm: &str) -> Option<Token> {
+        let start = self.tag_start;
+
+        self.read_until(rm);
+        let closed = self.starts_with(rm);
+
+        self.tag_start = None;
+        self.state = State::Normal;
+
+        if !closed {
+            if let Some(start) = start {
+                self.errors.push(self.lex_error(LexErrorKind::UnterminatedTag, start));
+            }
+            return None;
+        }
+
+        if self.standalone_pending {
+            self.standalone_pending = false;
+            self.consume_standalone_suffix();
+            self.pending_token = Some(Token::StandaloneMarker);
+        }
+
+        Some(Token::Bang)
+    }
+
+    // Either the implicit iterator `.` (when not followed by another id
+    // char) or, falling through, the start of an id/dotted-path handled by
+    // the regular `read_while(is_id)` below.
+    fn read_dot(&mut self) -> Option<Token> {
+        match self.read() {
+            Some(c) if is_id(c) => {
+                self.push_back_char(c);
+                self.push_back_char('.');
+                None
+            }
+            Some(c) => {
+                self.push_back_char(c);
+                Some(Token::Dot)
+            }
+            None => Some(Token::Dot)
+        }
+    }
+
+    // Where in `text` the run of trailing blanks that would precede an
+    // upcoming tag begins, if the position right before `text` is known to
+    // be "only blanks since the last newline" -- i.e. whether a tag
+    // starting right after `text` could be standalone. `None` if `text`
+    // has non-blank content on its last line.
+    fn blank_suffix_start(&self, text: &str) -> Option<usize> {
+        match text.rfind('\n') {
+            Some(idx) => {
+                let tail = &text[idx + 1..];
+                if tail.chars().all(|c| c == ' ' || c == '\t') {
+                    Some(idx + 1)
+                } else {
+                    None
+                }
+            }
+            None if self.at_line_start => {
+                if text.chars().all(|c| c == ' ' || c == '\t') {
+                    Some(0)
+                } else {
+                    None
+                }
+            }
+            None => None
+        }
+    }
+
+    // Update `at_line_start` for the line position left behind by a `Text`
+    // token that's being emitted as-is (not trimmed for a standalone tag).
+    fn commit_line_start(&mut self, text: &str) {
+        match text.rfind('\n') {
+            Some(idx) => {
+                self.at_line_start = text[idx + 1..].chars().all(|c| c == ' ' || c == '\t');
+            }
+            None => {
+                if !text.chars().all(|c| c == ' ' || c == '\t') {
+                    self.at_line_start = false;
+                }
+            }
+        }
+    }
+
+    // Non-destructively check whether the upcoming `lm` opens a
+    // section/comment/partial tag that sits alone on its line: `lm`,
+    // optional blanks, one of the standalone-eligible sigils, the tag
+    // body, `rm`, then optional blanks and a newline/EOF. `^` (inverted
+    // sections) and plain interpolation aren't included, matching the
+    // request's conservative scope. Always restores whatever it read.
+    fn peek_standalone_tag(&mut self) -> bool {
+        let mut consumed = String::new();
+        let result = self.peek_standalone_tag_inner(&mut consumed);
+        self.push_back_str(&consumed);
+        result
+    }
+
+    fn peek_standalone_tag_inner(&mut self, consumed: &mut String) -> bool {
+        let lm = self.lm.clone();
+        let rm = self.rm.clone();
+
+        for expected in lm.chars() {
+            match self.read() {
+                Some(c) if c == expected => consumed.push(c),
+                Some(c) => { self.push_back_char(c); return false; }
+                None => return false
+            }
+        }
+
+        self.peek_consume_blanks(consumed);
+
+        match self.read() {
+            Some(c) if c == '#' || c == '/' || c == '!' || c == '>' => consumed.push(c),
+            Some(c) => { self.push_back_char(c); return false; }
+            None => return false
+        }
+
+        loop {
+            match self.read() {
+                Some(c) => {
+                    consumed.push(c);
+                    if consumed.ends_with(&rm) {
+                        break;
+                    }
+                }
+                None => return false
+            }
+        }
+
+        self.peek_consume_blanks(consumed);
+
+        match self.read() {
+            Some(c) if c == '\n' => { consumed.push(c); true }
+            Some(c) => { self.push_back_char(c); false }
+            None => true
+        }
+    }
+
+    fn peek_consume_blanks(&mut self, consumed: &mut String) {
+        loop {
+            match self.read() {
+                Some(c) if c == ' ' || c == '\t' => consumed.push(c),
+                Some(c) => { self.push_back_char(c); break; }
+                None => break
+            }
+        }
+    }
+
+    // Actually consume the trailing blanks + newline already confirmed
+    // present by `peek_standalone_tag`, now that the tag has closed.
+    fn consume_standalone_suffix(&mut self) {
+        loop {
+            match self.read() {
+                Some(c) if c == ' ' || c == '\t' => {}
+                Some('\n') => break,
+                Some(c) => { self.push_back_char(c); break; }
+                None => break
+            }
+        }
+    }
+
     fn read_token(&mut self) -> Option<Token> {
+        if let Some(token) = self.pending_token.take() {
+            return Some(token);
+        }
+
         macro_rules! token_rules {
             ($($e:expr => $a:expr),*) => {{
                 $(if self.starts_with($e) { return Some($a); })* 
@@ -169,38 +809,146 @@ impl<'a, T : Iterator<Item = char>> Tokenlizer<'a, T> {
 
         match self.state {
             State::Normal => {
+                let start = self.mark();
+                // Peek before consuming `lm` below -- `peek_standalone_tag`
+                // expects to see `lm` still ahead of the cursor.
+                let is_standalone_tag = self.at_line_start && self.peek_standalone_tag();
+
                 if self.starts_with(&lm) {
+                    if is_standalone_tag {
+                        self.standalone_pending = true;
+                        self.at_line_start = true;
+                    } else {
+                        // A tag that isn't standalone is non-blank content
+                        // on this line.
+                        self.at_line_start = false;
+                    }
+
                     self.state = State::SyntaxToken;
-                    Some(Token::LMustache)
+                    self.tag_start = Some(start);
+                    self.tag_has_content = false;
+
+                    if self.starts_with("{") {
+                        self.triple = true;
+                        Some(Token::TripleLMustache)
+                    } else {
+                        Some(Token::LMustache)
+                    }
                 } else {
-                    self.read_until(&lm)
-                        .map(Token::Text)
+                    match self.read_until(&lm) {
+                        Some(text) => {
+                            if let Some(blank_start) = self.blank_suffix_start(&text) {
+                                if self.peek_standalone_tag() {
+                                    self.standalone_pending = true;
+                                    self.at_line_start = true;
+
+                                    let trimmed = text[..blank_start].to_string();
+                                    if trimmed.is_empty() {
+                                        return self.read_token();
+                                    }
+                                    return Some(Token::Text(trimmed));
+                                }
+                            }
+
+                            self.commit_line_start(&text);
+                            Some(Token::Text(text))
+                        }
+                        None => None
+                    }
                 }
             }
 
-            State::SyntaxToken => {
+            State::SyntaxToken => loop {
                 self.skip_ws();
 
+                if self.triple && self.starts_with(&format!("{}}}", rm)) {
+                    self.triple = false;
+                    self.tag_start = None;
+                    self.state = State::Normal;
+                    return Some(Token::TripleRMustache);
+                }
+
+                if self.starts_with("=") {
+                    return self.read_set_delimiter(&rm);
+                }
+
+                if self.starts_with("!") {
+                    return self.read_comment(&rm);
+                }
+
                 token_rules! {
-                    "#" => Token::Pound,
-                    "&" => Token::UnescapeTag,
-                    "/" => Token::Slash,
-                    "^" => Token::Hat,
+                    "#" => { self.tag_has_content = true; Token::Pound },
+                    "&" => { self.tag_has_content = true; Token::UnescapeTag },
+                    "/" => { self.tag_has_content = true; Token::Slash },
+                    "^" => { self.tag_has_content = true; Token::Hat },
+                    ">" => { self.tag_has_content = true; Token::GreaterThan },
                     &rm => {
+                        if !self.tag_has_content {
+                            if let Some(start) = self.tag_start {
+                                self.errors.push(self.lex_error(LexErrorKind::EmptyTagName, start));
+                            }
+                        }
+                        self.tag_start = None;
                         self.state = State::Normal;
+
+                        if self.standalone_pending {
+                            self.standalone_pending = false;
+                            self.consume_standalone_suffix();
+                            self.pending_token = Some(Token::StandaloneMarker);
+                        }
+
                         Token::RMustache
                     }
                 }
 
-                self.read_while(is_id)
-                    .map(Token::Id)
+                if self.starts_with(".") {
+                    if let Some(token) = self.read_dot() {
+                        self.tag_has_content = true;
+                        return Some(token);
+                    }
+                }
+
+                match self.read_while(is_id) {
+                    Some(id) => {
+                        self.tag_has_content = true;
+                        return Some(Token::Id(id));
+                    }
+                    None => match self.read() {
+                        None => {
+                            // Input ended without the tag ever closing.
+                            if let Some(start) = self.tag_start.take() {
+                                self.errors.push(self.lex_error(LexErrorKind::UnterminatedTag, start));
+                            }
+                            return None;
+                        }
+                        Some(_) => {
+                            // An unrecognized character inside the tag; skip
+                            // it and keep lexing the rest of the tag rather
+                            // than dropping the remaining input. Looping
+                            // here (instead of recursing into
+                            // `read_token`) keeps stack usage flat no
+                            // matter how many bad characters are skipped.
+                            self.tag_has_content = true;
+                            continue;
+                        }
+                    }
+                }
             }
         }
     }
 }
 
-impl <'a, T> Iterator for Tokenlizer<'a, T> 
-where T: 'a + Iterator<Item = char> 
+impl<'a, R: Read> Tokenlizer<'a, CharDecoder<R>> {
+    // Build a tokenizer over a raw byte stream, decoding it through a
+    // `CharDecoder` so callers don't have to decode templates themselves.
+    fn from_reader(lm: &str, rm: &str, reader: &'a mut CharDecoder<R>) -> Tokenlizer<'a, CharDecoder<R>> {
+        Self::new(lm, rm, reader)
+    }
+}
+
+#[cfg(not(feature = "span-locations"))]
+impl <'a, T> Iterator for Tokenlizer<'a, T>
+where T: 'a + Iterator<Item = char>
 {
     type Item = Token;
 
@@ -209,6 +957,25 @@ where T: 'a + Iterator<Item = char>
     }
 }
 
+#[cfg(feature = "span-locations")]
+impl <'a, T> Iterator for Tokenlizer<'a, T>
+where T: 'a + Iterator<Item = char>
+{
+    type Item = (Token, Span);
+
+    fn next(&mut self) -> Option<(Token, Span)> {
+        let start = self.pos;
+        let token = self.read_token()?;
+        let end = self.pos;
+
+        Some((token, Span {
+            start: LineColumn { line: start.line, column: start.column },
+            end: LineColumn { line: end.line, column: end.column },
+            byte_range: start.offset..end.offset
+        }))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::Tokenlizer;
@@ -231,10 +998,24 @@ mod test {
         assert_eq!(tokenlizer.read_str(3), Some("abc".to_string()));
     }
 
+    // Drains a tokenlizer into just its `Token`s, dropping the `Span` half
+    // of the item under `span-locations`, so the same test bodies run
+    // under both feature configurations.
+    fn collect_tokens<'a, T: Iterator<Item = char>>(tokenlizer: &mut Tokenlizer<'a, T>) -> Vec<Token> {
+        let mut result = Vec::new();
+        while let Some(item) = tokenlizer.next() {
+            #[cfg(feature = "span-locations")]
+            result.push(item.0);
+            #[cfg(not(feature = "span-locations"))]
+            result.push(item);
+        }
+        result
+    }
+
     fn test_parser(source: &str, expected : Vec<Token>) {
         let mut stream = source.chars();
-        let tokenlizer = Tokenlizer::new("{{", "}}", &mut stream);
-        let result : Vec<_> = tokenlizer.collect();
+        let mut tokenlizer = Tokenlizer::new("{{", "}}", &mut stream);
+        let result = collect_tokens(&mut tokenlizer);
         assert_eq!(result, expected);
     }
 
@@ -247,4 +1028,254 @@ mod test {
     fn test_parse_other_ops() {
         test_parser("abc{{^ # abc}}bcd", vec![Text("abc".to_string()), LMustache, Hat, Pound, Id("abc".to_string()), RMustache, Text("bcd".to_string())]);
     }
+
+    #[test]
+    fn test_parse_comment() {
+        test_parser("a{{! this is ignored }}b", vec![Text("a".to_string()), LMustache, Bang, Text("b".to_string())]);
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        // Alone on its line (the whole document, with EOF standing in for
+        // the trailing newline), so this is a standalone partial tag.
+        test_parser("{{> header}}", vec![LMustache, GreaterThan, Id("header".to_string()), RMustache, StandaloneMarker]);
+    }
+
+    #[test]
+    fn test_parse_triple_mustache() {
+        test_parser("{{{name}}}", vec![TripleLMustache, Id("name".to_string()), TripleRMustache]);
+    }
+
+    #[test]
+    fn test_parse_dotted_path() {
+        test_parser("{{a.b.c}}", vec![LMustache, Id("a.b.c".to_string()), RMustache]);
+    }
+
+    #[test]
+    fn test_parse_implicit_iterator() {
+        test_parser("{{.}}", vec![LMustache, Dot, RMustache]);
+    }
+
+    #[test]
+    fn test_parse_set_delimiter() {
+        test_parser("{{=<% %>=}}<%bcd%>", vec![
+            LMustache,
+            SetDelimiter { open: "<%".to_string(), close: "%>".to_string() },
+            LMustache,
+            Id("bcd".to_string()),
+            RMustache
+        ]);
+    }
+
+    #[test]
+    fn test_standalone_section_strips_surrounding_whitespace() {
+        test_parser("before\n  {{#a}}  \ninside\n  {{/a}}  \nafter", vec![
+            Text("before\n".to_string()),
+            LMustache, Pound, Id("a".to_string()), RMustache, StandaloneMarker,
+            Text("inside\n".to_string()),
+            LMustache, Slash, Id("a".to_string()), RMustache, StandaloneMarker,
+            Text("after".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_standalone_tag_at_start_of_document() {
+        test_parser("{{#a}}\ninside\n{{/a}}\nafter", vec![
+            LMustache, Pound, Id("a".to_string()), RMustache, StandaloneMarker,
+            Text("inside\n".to_string()),
+            LMustache, Slash, Id("a".to_string()), RMustache, StandaloneMarker,
+            Text("after".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_back_to_back_standalone_tags_without_intervening_text() {
+        test_parser("before\n{{#a}}\n{{#b}}\ninside\n{{/b}}\n{{/a}}\nafter", vec![
+            Text("before\n".to_string()),
+            LMustache, Pound, Id("a".to_string()), RMustache, StandaloneMarker,
+            LMustache, Pound, Id("b".to_string()), RMustache, StandaloneMarker,
+            Text("inside\n".to_string()),
+            LMustache, Slash, Id("b".to_string()), RMustache, StandaloneMarker,
+            LMustache, Slash, Id("a".to_string()), RMustache, StandaloneMarker,
+            Text("after".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_standalone_comment_strips_surrounding_whitespace() {
+        test_parser("before\n  {{! a comment }}  \nafter", vec![
+            Text("before\n".to_string()),
+            LMustache, Bang, StandaloneMarker,
+            Text("after".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_standalone_partial_strips_surrounding_whitespace() {
+        test_parser("before\n  {{> header}}  \nafter", vec![
+            Text("before\n".to_string()),
+            LMustache, GreaterThan, Id("header".to_string()), RMustache, StandaloneMarker,
+            Text("after".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_non_standalone_tag_is_unaffected() {
+        test_parser("before {{#a}}mid{{/a}} after", vec![
+            Text("before ".to_string()),
+            LMustache, Pound, Id("a".to_string()), RMustache,
+            Text("mid".to_string()),
+            LMustache, Slash, Id("a".to_string()), RMustache,
+            Text(" after".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_standalone_does_not_apply_to_interpolation() {
+        test_parser("before\n  {{name}}  \nafter", vec![
+            Text("before\n  ".to_string()),
+            LMustache, Id("name".to_string()), RMustache,
+            Text("  \nafter".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_unterminated_tag_reports_error() {
+        use super::LexErrorKind;
+
+        let mut stream = "abc{{ foo".chars();
+        let mut tokenlizer = Tokenlizer::new("{{", "}}", &mut stream);
+        let result = collect_tokens(&mut tokenlizer);
+
+        assert_eq!(result, vec![Text("abc".to_string()), LMustache, Id("foo".to_string())]);
+        assert_eq!(tokenlizer.errors().len(), 1);
+        assert_eq!(tokenlizer.errors()[0].kind, LexErrorKind::UnterminatedTag);
+    }
+
+    #[test]
+    fn test_empty_tag_name_reports_error() {
+        use super::LexErrorKind;
+
+        let mut stream = "{{}}".chars();
+        let mut tokenlizer = Tokenlizer::new("{{", "}}", &mut stream);
+        let result = collect_tokens(&mut tokenlizer);
+
+        assert_eq!(result, vec![LMustache, RMustache]);
+        assert_eq!(tokenlizer.errors().len(), 1);
+        assert_eq!(tokenlizer.errors()[0].kind, LexErrorKind::EmptyTagName);
+    }
+
+    #[test]
+    fn test_malformed_set_delimiter_reports_error() {
+        use super::LexErrorKind;
+
+        let mut stream = "{{=<%".chars();
+        let mut tokenlizer = Tokenlizer::new("{{", "}}", &mut stream);
+        let result = collect_tokens(&mut tokenlizer);
+
+        assert_eq!(result, vec![LMustache]);
+        assert_eq!(tokenlizer.errors().len(), 1);
+        assert_eq!(tokenlizer.errors()[0].kind, LexErrorKind::MalformedSetDelimiter);
+    }
+
+    fn decode(bytes: &[u8]) -> String {
+        super::CharDecoder::new(bytes).collect()
+    }
+
+    #[test]
+    fn test_decode_plain_utf8() {
+        assert_eq!(decode("abc{{bcd}}".as_bytes()), "abc{{bcd}}");
+    }
+
+    #[test]
+    fn test_decode_strips_utf8_bom() {
+        let mut bytes = vec![0xEF, 0xBB, 0xBF];
+        bytes.extend_from_slice("abc".as_bytes());
+        assert_eq!(decode(&bytes), "abc");
+    }
+
+    #[test]
+    fn test_decode_utf16le_bom() {
+        let mut bytes = vec![0xFF, 0xFE];
+        for unit in "ab".encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        assert_eq!(decode(&bytes), "ab");
+    }
+
+    #[test]
+    fn test_decode_utf16be_bom() {
+        let mut bytes = vec![0xFE, 0xFF];
+        for unit in "ab".encode_utf16() {
+            bytes.extend_from_slice(&unit.to_be_bytes());
+        }
+        assert_eq!(decode(&bytes), "ab");
+    }
+
+    #[test]
+    fn test_decode_falls_back_to_windows1252() {
+        // 0x93/0x94 are curly quotes in windows-1252, invalid as UTF-8 continuation bytes.
+        assert_eq!(decode(&[0x93, b'h', b'i', 0x94]), "\u{201C}hi\u{201D}");
+    }
+
+    #[test]
+    fn test_from_reader_tokenizes_like_chars() {
+        let mut decoder = super::CharDecoder::new("abc{{bcd}}".as_bytes());
+        let mut tokenlizer = Tokenlizer::from_reader("{{", "}}", &mut decoder);
+        let result = collect_tokens(&mut tokenlizer);
+        assert_eq!(result, vec![Text("abc".to_string()), LMustache, Id("bcd".to_string()), RMustache]);
+    }
+}
+
+#[cfg(all(test, feature = "span-locations"))]
+mod span_test {
+    use super::Tokenlizer;
+    use super::Token::*;
+    use super::{LineColumn, Span};
+
+    #[test]
+    fn test_span_tracks_line_and_column() {
+        let mut stream = "ab\ncd{{ef}}".chars();
+        let tokenlizer = Tokenlizer::new("{{", "}}", &mut stream);
+        let result : Vec<_> = tokenlizer.collect();
+
+        assert_eq!(result, vec![
+            (Text("ab\ncd".to_string()), Span {
+                start: LineColumn { line: 0, column: 0 },
+                end: LineColumn { line: 1, column: 2 },
+                byte_range: 0..5
+            }),
+            (LMustache, Span {
+                start: LineColumn { line: 1, column: 2 },
+                end: LineColumn { line: 1, column: 4 },
+                byte_range: 5..7
+            }),
+            (Id("ef".to_string()), Span {
+                start: LineColumn { line: 1, column: 4 },
+                end: LineColumn { line: 1, column: 6 },
+                byte_range: 7..9
+            }),
+            (RMustache, Span {
+                start: LineColumn { line: 1, column: 6 },
+                end: LineColumn { line: 1, column: 8 },
+                byte_range: 9..11
+            })
+        ]);
+    }
+
+    #[test]
+    fn test_push_back_restores_position_across_newline() {
+        let mut stream = "a\nb".chars();
+        let mut tokenlizer = Tokenlizer::new("{{", "}}", &mut stream);
+
+        assert_eq!(tokenlizer.read(), Some('a'));
+        assert_eq!(tokenlizer.read(), Some('\n'));
+        assert_eq!(tokenlizer.pos, super::Pos { offset: 2, line: 1, column: 0 });
+
+        tokenlizer.push_back_char('\n');
+        assert_eq!(tokenlizer.pos, super::Pos { offset: 1, line: 0, column: 1 });
+
+        assert_eq!(tokenlizer.read(), Some('\n'));
+        assert_eq!(tokenlizer.pos, super::Pos { offset: 2, line: 1, column: 0 });
+    }
 }